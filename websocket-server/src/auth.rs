@@ -0,0 +1,102 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::UfoError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_BYTES: usize = 32;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verifies backend connections with a nonce/HMAC challenge, falling back to
+/// a plain shared token checked against an Argon2id hash.
+pub(crate) struct BackendAuth {
+    secret: String,
+    secret_hash: String,
+}
+
+impl BackendAuth {
+    pub(crate) fn new(secret: String) -> Result<Self, UfoError> {
+        let salt = SaltString::generate(&mut OsRng);
+        // argon2::password_hash::Error only implements std::error::Error
+        // behind the non-default `std` feature, so this can't rely on the
+        // blanket UfoError conversion.
+        let secret_hash = Argon2::default()
+            .hash_password(secret.as_bytes(), &salt)
+            .map_err(|e| UfoError::Known(e.to_string()))?
+            .to_string();
+        Ok(Self { secret, secret_hash })
+    }
+
+    fn expected_response(&self, nonce: &str) -> Result<String, UfoError> {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())?;
+        mac.update(nonce.as_bytes());
+        Ok(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    /// Runs the Argon2id verification on a blocking-pool thread so a flood
+    /// of junk handshake attempts (reached before any authentication
+    /// exists) can't stall the tokio workers the frontend websockets share.
+    async fn verify_token(&self, token: &str) -> bool {
+        let secret_hash = self.secret_hash.clone();
+        let token = token.to_string();
+        tokio::task::spawn_blocking(move || match PasswordHash::new(&secret_hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(token.as_bytes(), &hash)
+                .is_ok(),
+            Err(e) => {
+                warn!("Stored backend secret hash is invalid: {:?}", e);
+                false
+            }
+        })
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Backend token verification task panicked: {:?}", e);
+            false
+        })
+    }
+
+    /// Sends a random nonce line to the backend and waits for either
+    /// `HMAC-SHA256(secret, nonce)` or the raw shared secret back on its own
+    /// line. Returns `Ok(true)` only if the response matches. Reads the
+    /// response line-delimited (rather than to EOF) so the same connection
+    /// stays open for further command frames after a successful handshake.
+    pub(crate) async fn handshake(&self, reader: &mut BufReader<TcpStream>) -> Result<bool, UfoError> {
+        let mut nonce_bytes = [0u8; NONCE_BYTES];
+        rand::thread_rng().fill_bytes(&mut nonce_bytes);
+        let nonce = hex::encode(nonce_bytes);
+
+        reader.get_mut().write_all(nonce.as_bytes()).await?;
+        reader.get_mut().write_all(b"\n").await?;
+
+        let mut response = String::new();
+        match timeout(HANDSHAKE_TIMEOUT, reader.read_line(&mut response)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                warn!("Backend handshake timed out waiting for a response");
+                return Ok(false);
+            }
+        }
+        let response = response.trim();
+
+        let expected = self.expected_response(&nonce)?;
+        Ok(constant_time_eq(response, &expected) || self.verify_token(response).await)
+    }
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,168 @@
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, Duration};
+use uuid::Uuid;
+
+use crate::State;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Caps each peer's outbound queue so a peer that's unreachable for a long
+/// time under steady broadcast traffic can't grow memory without bound;
+/// once full, the newest broadcast is dropped rather than queued.
+const MAX_QUEUED_PER_PEER: usize = 1024;
+
+/// A broadcast forwarded between federated nodes, tagged with the
+/// publishing node's id and a node-local message id so peers can
+/// deduplicate a broadcast that reaches them through more than one path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FederatedBroadcast {
+    origin: Uuid,
+    message_id: u64,
+    channel: String,
+    message: String,
+}
+
+/// Forwards locally-published broadcasts to every configured peer and
+/// deduplicates broadcasts received back from the cluster.
+pub(crate) struct Federation {
+    node_id: Uuid,
+    peers: Vec<mpsc::Sender<FederatedBroadcast>>,
+    /// Highest message id applied so far per origin node. Broadcasts from a
+    /// given origin are forwarded in order over a single persistent
+    /// connection, so tracking just the watermark (instead of every
+    /// `(origin, message_id)` pair ever seen) is enough to dedupe without
+    /// growing for the life of the process.
+    seen: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl Federation {
+    /// Spawns a persistent, reconnecting outbound task per peer and
+    /// returns the handle used to forward and deduplicate broadcasts.
+    pub(crate) fn start(node_id: Uuid, peer_addrs: Vec<String>) -> Arc<Self> {
+        let peers = peer_addrs
+            .into_iter()
+            .map(|addr| {
+                let (sender, receiver) = mpsc::channel(MAX_QUEUED_PER_PEER);
+                tokio::spawn(peer_connection(addr, receiver));
+                sender
+            })
+            .collect();
+
+        Arc::new(Self {
+            node_id,
+            peers,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Forwards a locally-originated broadcast to every peer.
+    pub(crate) fn forward(&self, channel: &str, message: &str, message_id: u64) {
+        let frame = FederatedBroadcast {
+            origin: self.node_id,
+            message_id,
+            channel: channel.to_string(),
+            message: message.to_string(),
+        };
+        for peer in &self.peers {
+            if let Err(e) = peer.try_send(frame.clone()) {
+                warn!("Queueing broadcast for federation peer: {:?}", e);
+            }
+        }
+    }
+
+    /// Returns `true` the first time `message_id` is seen from `origin`
+    /// (i.e. it's newer than the last one applied), so the caller applies
+    /// it locally exactly once and never re-forwards it.
+    async fn mark_seen(&self, origin: Uuid, message_id: u64) -> bool {
+        let mut seen = self.seen.lock().await;
+        match seen.get(&origin) {
+            Some(&highest) if message_id <= highest => false,
+            _ => {
+                seen.insert(origin, message_id);
+                true
+            }
+        }
+    }
+}
+
+async fn peer_connection(addr: String, mut outbound: mpsc::Receiver<FederatedBroadcast>) {
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(mut socket) => {
+                info!("Federation connected to peer {}", addr);
+                while let Some(frame) = outbound.recv().await {
+                    let line = match serde_json::to_string(&frame) {
+                        Ok(line) => line,
+                        Err(e) => {
+                            error!("Encoding federated broadcast: {:?}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = socket.write_all(format!("{}\n", line).as_bytes()).await {
+                        warn!("Federation peer {} disconnected: {:?}", addr, e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to connect to federation peer {}: {:?}", addr, e),
+        }
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Listens for inbound connections from federation peers and applies the
+/// broadcasts they forward to this node's local subscribers.
+pub(crate) async fn listen(addr: (IpAddr, u16), state: Arc<State>, federation: Arc<Federation>) {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("Federation listening on {}:{}", addr.0, addr.1);
+            loop {
+                match listener.accept().await {
+                    Ok((socket, peer_addr)) => {
+                        info!("Federation incoming connection from {:?}", peer_addr);
+                        tokio::spawn(handle_inbound(socket, state.clone(), federation.clone()));
+                    }
+                    Err(e) => error!("Federation accept failed: {:?}", e),
+                }
+            }
+        }
+        Err(e) => error!("Federation failed to bind: {:?}", e),
+    }
+}
+
+async fn handle_inbound(socket: TcpStream, state: Arc<State>, federation: Arc<Federation>) {
+    let mut lines = BufReader::new(socket).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match serde_json::from_str::<FederatedBroadcast>(&line) {
+                Ok(frame) => apply(&state, &federation, frame).await,
+                Err(e) => warn!("Invalid federated broadcast frame: {:?}", e),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                error!("Reading federation connection: {:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+async fn apply(state: &Arc<State>, federation: &Arc<Federation>, frame: FederatedBroadcast) {
+    if frame.origin == federation.node_id {
+        return;
+    }
+    if !federation.mark_seen(frame.origin, frame.message_id).await {
+        debug!(
+            "Dropping already-applied federated broadcast {}:{}",
+            frame.origin, frame.message_id
+        );
+        return;
+    }
+    state.apply_federated_broadcast(&frame.channel, &frame.message).await;
+}
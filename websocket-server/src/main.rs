@@ -1,17 +1,30 @@
+use chrono::{DateTime, Utc};
 use config::Config;
 use env_logger;
 use futures::{FutureExt, StreamExt};
 use log::{debug, error, info, warn, LevelFilter};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, collections::HashSet, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashMap, collections::HashSet, collections::VecDeque, net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering}, sync::Arc,
+};
 use syslog::{BasicLogger, Facility, Formatter3164};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, Duration, Instant};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use uuid::Uuid;
 use warp::{ws::Message, ws::WebSocket, Filter};
 
+mod auth;
+mod federation;
+mod metrics;
+mod subject;
+use auth::BackendAuth;
+use federation::Federation;
+use metrics::Metrics;
+
 type Sender = mpsc::UnboundedSender<Result<Message, warp::Error>>;
 
 #[derive(Debug, Clone)]
@@ -19,6 +32,15 @@ struct Client {
     sender: Option<Sender>,
     permissions: Vec<String>,
     ready: bool,
+    /// Presented back to a reconnecting client to prove it owns this
+    /// session; required to resume a detached client.
+    resume_token: Uuid,
+    /// Set when the client's websocket has dropped but its session is
+    /// still held open for a reconnect within the resume grace period.
+    detached_at: Option<Instant>,
+    /// The highest message id already delivered to this client when it
+    /// detached, so a resume only replays what was published during the gap.
+    detached_since: Option<u64>,
 }
 
 impl Client {
@@ -27,31 +49,67 @@ impl Client {
             sender: Some(sender),
             permissions: vec![],
             ready: false,
+            resume_token: Uuid::new_v4(),
+            detached_at: None,
+            detached_since: None,
         }
     }
 }
 
+/// A single broadcast retained in a channel's history ring buffer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredMessage {
+    id: u64,
+    message: String,
+    timestamp: DateTime<Utc>,
+}
+
 struct State {
     clients: Mutex<HashMap<Uuid, Client>>,
     subscriptions: Mutex<HashMap<String, HashSet<Uuid>>>,
+    history: Mutex<HashMap<String, VecDeque<StoredMessage>>>,
+    history_len: usize,
+    next_message_id: AtomicU64,
+    metrics: Metrics,
+    federation: Option<Arc<Federation>>,
+    resume_grace_period: Duration,
 }
 
 impl State {
-    fn new() -> Arc<Self> {
+    fn new(
+        history_len: usize,
+        federation: Option<Arc<Federation>>,
+        resume_grace_period: Duration,
+    ) -> Arc<Self> {
         Arc::new(Self {
             clients: Mutex::new(HashMap::new()),
             subscriptions: Mutex::new(HashMap::new()),
+            history: Mutex::new(HashMap::new()),
+            history_len,
+            next_message_id: AtomicU64::new(0),
+            metrics: Metrics::new(),
+            federation,
+            resume_grace_period,
         })
     }
 
     async fn new_client(&self, sender: Sender) -> Uuid {
         let uuid = Uuid::new_v4();
+        let client = Client::new(sender);
+        let resume_token = client.resume_token;
         let mut locked_clients = self.clients.lock().await;
-        locked_clients.insert(uuid, Client::new(sender));
+        locked_clients.insert(uuid, client);
         info!("Client {} connected", uuid);
+        self.metrics.connections_total.inc();
+        self.metrics.clients_connected.inc();
         if let Some(client) = locked_clients.get(&uuid) {
             if let Some(sender) = &client.sender {
-                if let Err(e) = (UfoMessage::Uid { uid: uuid }).send(sender) {
+                if let Err(e) = (UfoMessage::Uid {
+                    uid: uuid,
+                    resume_token,
+                })
+                .send(sender)
+                {
                     error!("Sending uid to client: {:?}", e);
                 }
             }
@@ -59,7 +117,8 @@ impl State {
         uuid
     }
 
-    async fn set_permission(&self, client_id: Uuid, permissions: &Vec<&str>) {
+    /// Returns `false` if `client_id` is not a known, connected client.
+    async fn set_permission(&self, client_id: Uuid, permissions: &Vec<&str>) -> bool {
         let mut locked_clients = self.clients.lock().await;
         if let Some(client) = locked_clients.get_mut(&client_id) {
             for permission in permissions {
@@ -77,81 +136,379 @@ impl State {
                 }
                 client.ready = true;
             }
+            true
+        } else {
+            false
         }
     }
 
-    async fn add_subscription(&self, client_id: Uuid, channel: &str) {
+    async fn add_subscription(&self, client_id: Uuid, pattern: &str) {
         /* Important:
          * if any other function needs to lock both subscriptions and clients,
          * subscriptions need to be locked first to prevent deadlocks.
          */
         let mut locked_subscriptions = self.subscriptions.lock().await;
         let locked_clients = self.clients.lock().await;
-        if let Some(client) = locked_clients.get(&client_id) {
-            if client.permissions.iter().any(|p| p == channel) {
-                if let Some(client_list) = locked_subscriptions.get_mut(channel) {
-                    client_list.insert(client_id);
+        let sender = match locked_clients.get(&client_id) {
+            Some(client)
+                if client
+                    .permissions
+                    .iter()
+                    .any(|granted| subject::authorizes(granted, pattern)) =>
+            {
+                let newly_subscribed = if let Some(client_list) = locked_subscriptions.get_mut(pattern) {
+                    client_list.insert(client_id)
                 } else {
                     let mut set = HashSet::new();
                     set.insert(client_id);
-                    locked_subscriptions.insert(channel.to_string(), set);
+                    locked_subscriptions.insert(pattern.to_string(), set);
+                    true
+                };
+                if newly_subscribed {
+                    self.metrics.subscriptions.inc();
                 }
-                debug!("Client {} subscribed to {:?}", client_id, channel);
-            } else {
+                debug!("Client {} subscribed to {:?}", client_id, pattern);
+                client.sender.clone()
+            }
+            Some(_) => {
                 warn!(
                     "Client {} tried subscribing to {:?} but didn't have permission",
-                    client_id, channel
+                    client_id, pattern
                 );
+                return;
+            }
+            None => return,
+        };
+        // Captured only once the subscription above is already live, so any
+        // broadcast appended from here on reaches the client through the
+        // normal live broadcast path and is never also replayed below.
+        let until = self.next_message_id.load(Ordering::Relaxed);
+        drop(locked_clients);
+        drop(locked_subscriptions);
+
+        if let Some(sender) = sender {
+            for (channel, stored) in self.history_matching(pattern, None).await {
+                if stored.id >= until {
+                    continue;
+                }
+                if let Err(e) = send_stored_message(&sender, &channel, &stored) {
+                    error!("Replaying history to client {}: {:?}", client_id, e);
+                }
             }
         }
     }
 
+    async fn replay_history(&self, client_id: Uuid, pattern: &str, since: Option<u64>) {
+        let locked_clients = self.clients.lock().await;
+        let client = match locked_clients.get(&client_id) {
+            Some(client) => client,
+            None => return,
+        };
+        if !client
+            .permissions
+            .iter()
+            .any(|granted| subject::authorizes(granted, pattern))
+        {
+            warn!(
+                "Client {} requested history for {:?} without permission",
+                client_id, pattern
+            );
+            return;
+        }
+        let sender = match &client.sender {
+            Some(sender) => sender.clone(),
+            None => return,
+        };
+        drop(locked_clients);
+
+        for (channel, stored) in self.history_matching(pattern, since).await {
+            if let Err(e) = send_stored_message(&sender, &channel, &stored) {
+                error!("Replaying history to client {}: {:?}", client_id, e);
+            }
+        }
+    }
+
+    /// Returns the buffered messages on every channel matching the
+    /// (possibly wildcarded) `pattern` with an id greater than `since` (or
+    /// all of them, if `since` is `None`), oldest first, alongside the
+    /// concrete channel each one was published on.
+    async fn history_matching(
+        &self,
+        pattern: &str,
+        since: Option<u64>,
+    ) -> Vec<(String, StoredMessage)> {
+        let locked_history = self.history.lock().await;
+        let mut matched: Vec<(String, StoredMessage)> = locked_history
+            .iter()
+            .filter(|(channel, _)| subject::matches(pattern, channel))
+            .flat_map(|(channel, buffer)| {
+                buffer
+                    .iter()
+                    .filter(|stored| since.map_or(true, |since| stored.id > since))
+                    .map(move |stored| (channel.clone(), stored.clone()))
+            })
+            .collect();
+        matched.sort_by_key(|(_, stored)| stored.id);
+        matched
+    }
+
+    async fn append_history(&self, channel: &str, message: &str) -> StoredMessage {
+        let stored = StoredMessage {
+            id: self.next_message_id.fetch_add(1, Ordering::Relaxed),
+            message: message.to_string(),
+            timestamp: Utc::now(),
+        };
+        let mut locked_history = self.history.lock().await;
+        let buffer = locked_history
+            .entry(channel.to_string())
+            .or_insert_with(VecDeque::new);
+        buffer.push_back(stored.clone());
+        while buffer.len() > self.history_len {
+            buffer.pop_front();
+        }
+        stored
+    }
+
     async fn reset_subscription(&self, client_id: Uuid) {
         let mut locked_subscriptions = self.subscriptions.lock().await;
-        for (_channel, client_list) in locked_subscriptions.iter_mut() {
-            client_list.retain(|uuid| uuid != &client_id);
+        for (_, client_list) in locked_subscriptions.iter_mut() {
+            if client_list.remove(&client_id) {
+                self.metrics.subscriptions.dec();
+            }
         }
     }
 
-    async fn client_disconnect(&self, client_id: Uuid) {
-        self.reset_subscription(client_id).await;
+    /// Detaches `client_id` rather than dropping it immediately, so a
+    /// reconnecting client can resume the same session (subscriptions and
+    /// permissions intact) within the configured grace period.
+    async fn client_disconnect(self: Arc<Self>, client_id: Uuid) {
+        self.metrics.disconnections_total.inc();
+        self.metrics.clients_connected.dec();
+
+        let resume_token = {
+            let mut locked_clients = self.clients.lock().await;
+            match locked_clients.get_mut(&client_id) {
+                Some(client) => {
+                    client.sender = None;
+                    client.resume_token = Uuid::new_v4();
+                    client.detached_at = Some(Instant::now());
+                    client.detached_since = self.next_message_id.load(Ordering::Relaxed).checked_sub(1);
+                    Some(client.resume_token)
+                }
+                None => None,
+            }
+        };
+
+        let resume_token = match resume_token {
+            Some(resume_token) => resume_token,
+            None => return,
+        };
+        info!(
+            "Client {} detached, session held for {:?}",
+            client_id, self.resume_grace_period
+        );
+
+        let state = self.clone();
+        let grace_period = self.resume_grace_period;
+        tokio::spawn(async move {
+            sleep(grace_period).await;
+            state.finalize_disconnect(client_id, resume_token).await;
+        });
+    }
+
+    /// Drops `client_id` for good if it is still detached with the resume
+    /// token it had when the grace period began, i.e. it was not resumed.
+    /// A session that was resumed and detached again since carries a new
+    /// token and is left to the timer of its later disconnect.
+    async fn finalize_disconnect(&self, client_id: Uuid, resume_token: Uuid) {
+        let still_detached = {
+            let locked_clients = self.clients.lock().await;
+            locked_clients.get(&client_id).map_or(false, |client| {
+                client.detached_at.is_some() && client.resume_token == resume_token
+            })
+        };
+        if !still_detached {
+            return;
+        }
         self.clients.lock().await.remove(&client_id);
-        info!("Client {} disconnected", client_id);
+        self.reset_subscription(client_id).await;
+        info!("Client {} resume grace period expired, session dropped", client_id);
+    }
+
+    /// Reattaches a detached session (`target_uid`) to the connection
+    /// currently identified as `current_id`, replaying only the history the
+    /// client's channels accumulated while it was detached. Returns the
+    /// resumed uuid the caller should use from now on.
+    async fn resume_session(
+        &self,
+        current_id: Uuid,
+        target_uid: Uuid,
+        resume_token: Uuid,
+    ) -> Option<Uuid> {
+        let (sender, new_resume_token, since, until) = {
+            let mut locked_clients = self.clients.lock().await;
+            let sender = locked_clients.get(&current_id)?.sender.clone()?;
+
+            let target = locked_clients.get_mut(&target_uid)?;
+            if target.detached_at.is_none() || target.resume_token != resume_token {
+                warn!(
+                    "Client {} failed to resume session {}: invalid or already-live token",
+                    current_id, target_uid
+                );
+                return None;
+            }
+            target.sender = Some(sender.clone());
+            target.detached_at = None;
+            // Rotate the token so the grace-period timer scheduled for this
+            // disconnect can no longer match and tear down the now-live session.
+            target.resume_token = Uuid::new_v4();
+            // Captured right after the sender goes live, so anything
+            // broadcast from this point on reaches the client through the
+            // normal live broadcast path and is never also replayed below.
+            let until = self.next_message_id.load(Ordering::Relaxed);
+            (sender, target.resume_token, target.detached_since.take(), until)
+        };
+
+        self.clients.lock().await.remove(&current_id);
+        self.reset_subscription(current_id).await;
+        info!("Client {} resumed session {}", current_id, target_uid);
+
+        if let Err(e) = (UfoMessage::Uid {
+            uid: target_uid,
+            resume_token: new_resume_token,
+        })
+        .send(&sender)
+        {
+            error!("Re-sending uid after resume: {:?}", e);
+        }
+
+        for pattern in self.channels_for_client(target_uid).await {
+            for (channel, stored) in self.history_matching(&pattern, since).await {
+                if stored.id >= until {
+                    continue;
+                }
+                if let Err(e) = send_stored_message(&sender, &channel, &stored) {
+                    error!("Replaying history after resume: {:?}", e);
+                }
+            }
+        }
+
+        Some(target_uid)
+    }
+
+    async fn channels_for_client(&self, client_id: Uuid) -> Vec<String> {
+        let locked_subscriptions = self.subscriptions.lock().await;
+        locked_subscriptions
+            .iter()
+            .filter(|(_, clients)| clients.contains(&client_id))
+            .map(|(pattern, _)| pattern.clone())
+            .collect()
+    }
+
+    /// Broadcasts `message` on `channel`, forwards it to any federation
+    /// peers, and returns how many local clients it was delivered to.
+    async fn broadcast_message(&self, channel: &str, message: &str) -> usize {
+        let (_, delivered) = self.deliver_locally(channel, message, true).await;
+        delivered
+    }
+
+    /// Applies a broadcast forwarded from a federation peer to this node's
+    /// local subscribers, without forwarding it onward.
+    async fn apply_federated_broadcast(&self, channel: &str, message: &str) {
+        self.deliver_locally(channel, message, false).await;
     }
 
-    async fn broadcast_message(&self, channel: &str, message: &str) {
+    /// Stores `message` in the channel's history, forwards it to any
+    /// federation peers if `forward` is set, and delivers it to every
+    /// local subscriber, returning the stored record and delivery count.
+    async fn deliver_locally(
+        &self,
+        channel: &str,
+        message: &str,
+        forward: bool,
+    ) -> (StoredMessage, usize) {
         /* Important:
          * if any other function needs to lock both subscriptions and clients,
-         * subscriptions need to be locked first to prevent deadlocks.
+         * subscriptions need to be locked first to prevent deadlocks. The
+         * message is stored (and so assigned its id) only after clients is
+         * also locked, so the id a broadcast gets is ordered atomically
+         * against any concurrent add_subscription/resume_session, both of
+         * which also hold clients while reading next_message_id as a replay
+         * watermark - without that, a message could be counted into the
+         * watermark before (or well after) this function actually reaches
+         * the subscriber it's meant to gate, and get delivered twice.
          */
         let locked_subscriptions = self.subscriptions.lock().await;
-        if let Some(client_list) = locked_subscriptions.get(channel) {
-            let locked_clients = self.clients.lock().await;
-            let msg = match (UfoMessage::Broadcast { channel, message }).output() {
-                Ok(msg) => msg,
-                Err(e) => {
-                    error!("Building broadcast: {:?}", e);
-                    return;
-                }
-            };
-            debug!(
-                "Broadcasting to {} clients on channel {:?}",
-                client_list.len(),
-                channel
-            );
-            for client_id in client_list {
-                if let Some(client) = locked_clients.get(client_id) {
-                    if let Some(sender) = &client.sender {
-                        if let Err(e) = sender.send(Ok(Message::text(&msg))) {
+        let locked_clients = self.clients.lock().await;
+
+        let stored = self.append_history(channel, message).await;
+        // Forwarded while both locks are still held, so each peer's queue
+        // receives this node's broadcasts in message id order.
+        if forward {
+            if let Some(federation) = &self.federation {
+                federation.forward(channel, message, stored.id);
+            }
+        }
+
+        let subscribers: HashSet<Uuid> = locked_subscriptions
+            .iter()
+            .filter(|(pattern, _)| subject::matches(pattern, channel))
+            .flat_map(|(_, client_list)| client_list.iter().copied())
+            .collect();
+        if subscribers.is_empty() {
+            return (stored, 0);
+        }
+
+        let msg = match (UfoMessage::Broadcast {
+            channel,
+            message,
+            id: stored.id,
+            timestamp: stored.timestamp,
+        })
+        .output()
+        {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Building broadcast: {:?}", e);
+                return (stored, 0);
+            }
+        };
+        debug!(
+            "Broadcasting to {} clients on channel {:?}",
+            subscribers.len(),
+            channel
+        );
+        self.metrics.broadcasts_total.inc();
+        let mut delivered = 0;
+        for client_id in &subscribers {
+            if let Some(client) = locked_clients.get(client_id) {
+                if let Some(sender) = &client.sender {
+                    match sender.send(Ok(Message::text(&msg))) {
+                        Ok(()) => {
+                            delivered += 1;
+                            self.metrics.bytes_sent_total.inc_by(msg.len() as u64);
+                        }
+                        Err(e) => {
                             error!("Broadcasting to clients on channel {:?}: {:?}", channel, e)
                         }
                     }
                 }
             }
         }
+        (stored, delivered)
     }
 }
 
+fn send_stored_message(sender: &Sender, channel: &str, stored: &StoredMessage) -> Result<(), UfoError> {
+    (UfoMessage::Broadcast {
+        channel,
+        message: &stored.message,
+        id: stored.id,
+        timestamp: stored.timestamp,
+    })
+    .send(sender)
+}
+
 #[derive(Debug)]
 enum LoggingMethod {
     Syslog(String),
@@ -216,13 +573,24 @@ enum UfoMessage<'a> {
     Broadcast {
         channel: &'a str,
         message: &'a str,
+        id: u64,
+        timestamp: DateTime<Utc>,
     },
     Uid {
         uid: Uuid,
+        resume_token: Uuid,
     },
     Subscribe {
         channel: &'a str,
     },
+    History {
+        channel: &'a str,
+        since: Option<u64>,
+    },
+    Resume {
+        uid: Uuid,
+        resume_token: Uuid,
+    },
     Ready,
 }
 
@@ -236,6 +604,24 @@ impl<'a> UfoMessage<'a> {
     }
 }
 
+/// A NATS-style acknowledgement written back over the backend's `TcpStream`
+/// after each handled command frame.
+#[derive(Debug)]
+enum Ack {
+    Ok { delivered: Option<usize> },
+    Err(String),
+}
+
+impl Ack {
+    fn to_line(&self) -> String {
+        match self {
+            Ack::Ok { delivered: Some(count) } => format!("+OK {}\n", count),
+            Ack::Ok { delivered: None } => "+OK\n".to_string(),
+            Ack::Err(reason) => format!("-ERR {}\n", reason),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum UfoError {
     Known(String),
@@ -252,15 +638,30 @@ where
 
 #[tokio::main]
 async fn main() {
-    let state = State::new();
-
     let config_builder = Config::builder().add_source(config::File::with_name("config"));
 
-    let (host, frontend_port, backend_port, logging_method) = match config_builder.build() {
+    let (
+        host,
+        frontend_port,
+        backend_port,
+        metrics_port,
+        federation_port,
+        backend_secret,
+        history_len,
+        federation_peers,
+        resume_grace_period_secs,
+        logging_method,
+    ) = match config_builder.build() {
         Ok(config) => (
             config.get("host").unwrap_or_else(|_| [127, 0, 0, 1].into()),
             config.get("port_frontend").unwrap_or(8080),
             config.get("port_backend").unwrap_or(8081),
+            config.get("port_metrics").unwrap_or(9090),
+            config.get("port_federation").unwrap_or(8082),
+            config.get::<String>("backend_secret").ok(),
+            config.get("history_len").unwrap_or(50),
+            config.get::<Vec<String>>("federation_peers").unwrap_or_default(),
+            config.get("resume_grace_period_secs").unwrap_or(60),
             LoggingMethod::from_config(config),
         ),
         Err(e) => {
@@ -268,13 +669,49 @@ async fn main() {
                 "Failed reading config file. Using default values. ({:?})",
                 e
             );
-            ([127, 0, 0, 1].into(), 8080, 8081, LoggingMethod::Print)
+            (
+                [127, 0, 0, 1].into(),
+                8080,
+                8081,
+                9090,
+                8082,
+                None,
+                50,
+                Vec::new(),
+                60,
+                LoggingMethod::Print,
+            )
         }
     };
 
     logging_method.init();
 
-    let backend = backend_server(state.clone(), (host, backend_port));
+    // A node with no outbound peers still has to listen for inbound
+    // federation connections from peers that forward to it (e.g. a hub);
+    // listening must not be gated on this node itself dialing out.
+    let federation = Federation::start(Uuid::new_v4(), federation_peers);
+
+    let state = State::new(
+        history_len,
+        Some(federation.clone()),
+        Duration::from_secs(resume_grace_period_secs),
+    );
+
+    let backend_auth = match backend_secret {
+        Some(secret) => match BackendAuth::new(secret) {
+            Ok(auth) => Some(auth),
+            Err(e) => {
+                error!("Failed to initialize backend auth, refusing to start: {:?}", e);
+                return;
+            }
+        },
+        None => {
+            warn!("No backend_secret configured; backend connections are not authenticated");
+            None
+        }
+    };
+
+    let backend = backend_server(state.clone(), (host, backend_port), Arc::new(backend_auth));
 
     let state_clone = state.clone();
     let frontend_websocket = warp::ws().map(move |ws: warp::ws::Ws| {
@@ -282,21 +719,28 @@ async fn main() {
         ws.on_upgrade(move |socket| client_connection(socket, state))
     });
 
+    let state_clone = state.clone();
+    let metrics_route = warp::path("metrics").map(move || state_clone.metrics.encode());
+
     info!("Frontend listening on {}:{}", host, frontend_port);
+    info!("Metrics listening on {}:{}", host, metrics_port);
+    let federation_listener = federation::listen((host, federation_port), state.clone(), federation);
     tokio::join!(
         warp::serve(frontend_websocket).run((host, frontend_port)),
-        backend
+        warp::serve(metrics_route).run((host, metrics_port)),
+        backend,
+        federation_listener
     );
 
     info!("Program ended");
 }
 
-async fn backend_server(state: Arc<State>, addr: (IpAddr, u16)) {
+async fn backend_server(state: Arc<State>, addr: (IpAddr, u16), auth: Arc<Option<BackendAuth>>) {
     match TcpListener::bind(addr).await {
         Ok(listener) => {
             info!("Backend listening on {}:{}", addr.0, addr.1);
             loop {
-                if let Err(e) = backend_listen(&listener, &state).await {
+                if let Err(e) = backend_listen(&listener, &state, &auth).await {
                     error!("Failed to listen: {:?}", e);
                 }
             }
@@ -305,41 +749,112 @@ async fn backend_server(state: Arc<State>, addr: (IpAddr, u16)) {
     }
 }
 
-async fn backend_listen(listener: &TcpListener, state: &Arc<State>) -> Result<(), UfoError> {
+async fn backend_listen(
+    listener: &TcpListener,
+    state: &Arc<State>,
+    auth: &Arc<Option<BackendAuth>>,
+) -> Result<(), UfoError> {
     let (socket, _addr) = listener.accept().await?;
 
-    tokio::spawn(backend_connection(socket, state.clone()));
+    tokio::spawn(backend_connection(socket, state.clone(), auth.clone()));
     Ok(())
 }
 
-async fn backend_connection(mut socket: TcpStream, state: Arc<State>) {
+async fn backend_connection(socket: TcpStream, state: Arc<State>, auth: Arc<Option<BackendAuth>>) {
     if let Ok(addr) = socket.peer_addr() {
         info!("Backend incoming connection from {:?}", addr);
     }
-    let mut string_buffer = String::new();
+    let mut reader = BufReader::new(socket);
+
+    let authenticated = match auth.as_ref() {
+        Some(backend_auth) => match backend_auth.handshake(&mut reader).await {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!("Backend connection failed authentication, dropping");
+                return;
+            }
+            Err(e) => {
+                warn!("Backend handshake error, dropping connection: {:?}", e);
+                return;
+            }
+        },
+        None => true,
+    };
+
     loop {
-        if let Err(e) = backend_handle(&mut socket, &state, &mut string_buffer).await {
+        if let Err(e) = backend_handle(&mut reader, &state, authenticated).await {
             error!("Error handling message from backend: {:?}", e);
         }
     }
 }
 
 async fn backend_handle(
-    socket: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
     state: &Arc<State>,
-    buffer: &mut String,
+    authenticated: bool,
 ) -> Result<(), UfoError> {
-    let bytes_read = socket.read_to_string(buffer).await?;
+    let mut line = String::new();
+    let bytes_read = reader.read_line(&mut line).await?;
     if bytes_read == 0 {
         return Ok(());
     }
+    let line = line.trim();
+
+    let parsed: UfoMessage = match serde_json::from_str(line) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            state
+                .metrics
+                .backend_frames_total
+                .with_label_values(&["rejected"])
+                .inc();
+            write_ack(reader.get_mut(), &Ack::Err(format!("invalid frame: {:?}", e))).await?;
+            return Err(e.into());
+        }
+    };
+
     use UfoMessage::*;
-    match serde_json::from_str(buffer)? {
-        Permission { uid, permissions } => state.set_permission(uid, &permissions).await,
-        Message { channel, message } => state.broadcast_message(channel, message).await,
-        _ => (),
+    let ack = match parsed {
+        Permission { uid, permissions } if authenticated => {
+            if state.set_permission(uid, &permissions).await {
+                Ack::Ok { delivered: None }
+            } else {
+                Ack::Err(format!("unknown uid {}", uid))
+            }
+        }
+        Message { channel, message } if authenticated => {
+            let delivered = state.broadcast_message(channel, message).await;
+            if delivered == 0 {
+                Ack::Err(format!("no subscribers for channel {:?}", channel))
+            } else {
+                Ack::Ok {
+                    delivered: Some(delivered),
+                }
+            }
+        }
+        Permission { .. } | Message { .. } => {
+            warn!("Rejected command frame from an unauthenticated backend connection");
+            Ack::Err("not authenticated".to_string())
+        }
+        _ => return Ok(()),
     };
 
+    let result_label = if matches!(ack, Ack::Ok { .. }) {
+        "processed"
+    } else {
+        "rejected"
+    };
+    state
+        .metrics
+        .backend_frames_total
+        .with_label_values(&[result_label])
+        .inc();
+
+    write_ack(reader.get_mut(), &ack).await
+}
+
+async fn write_ack(socket: &mut TcpStream, ack: &Ack) -> Result<(), UfoError> {
+    socket.write_all(ack.to_line().as_bytes()).await?;
     Ok(())
 }
 
@@ -355,10 +870,10 @@ async fn client_connection(ws: WebSocket, state: Arc<State>) {
         }
     }));
 
-    let uuid = state.new_client(client_sender).await;
+    let mut uuid = state.new_client(client_sender).await;
 
     while let Some(result) = client_ws_rcv.next().await {
-        if let Err(e) = client_msg(uuid, result, &state).await {
+        if let Err(e) = client_msg(&mut uuid, result, &state).await {
             error!("Error handling message from client: {:?}", e);
         }
     }
@@ -366,14 +881,24 @@ async fn client_connection(ws: WebSocket, state: Arc<State>) {
 }
 
 async fn client_msg(
-    client_id: Uuid,
+    client_id: &mut Uuid,
     message: Result<Message, warp::Error>,
     state: &Arc<State>,
 ) -> Result<(), UfoError> {
     let message = message?;
     if let Ok(msg) = message.to_str() {
         match serde_json::from_str(msg)? {
-            UfoMessage::Subscribe { channel } => state.add_subscription(client_id, channel).await,
+            UfoMessage::Subscribe { channel } => {
+                state.add_subscription(*client_id, channel).await
+            }
+            UfoMessage::History { channel, since } => {
+                state.replay_history(*client_id, channel, since).await
+            }
+            UfoMessage::Resume { uid, resume_token } => {
+                if let Some(resumed) = state.resume_session(*client_id, uid, resume_token).await {
+                    *client_id = resumed;
+                }
+            }
             _ => (),
         }
     };
@@ -0,0 +1,102 @@
+use log::error;
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Prometheus counters and gauges describing a running ufo-ajax instance,
+/// served in text format on `/metrics`.
+///
+/// `subscriptions` and `broadcasts_total` are kept as single aggregate
+/// metrics rather than labeled per channel: channel and subscription
+/// patterns are client/backend-supplied strings with no cardinality bound,
+/// so a per-channel label would let the registry grow without limit.
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub(crate) connections_total: IntCounter,
+    pub(crate) disconnections_total: IntCounter,
+    pub(crate) clients_connected: IntGauge,
+    pub(crate) subscriptions: IntGauge,
+    pub(crate) broadcasts_total: IntCounter,
+    pub(crate) bytes_sent_total: IntCounter,
+    pub(crate) backend_frames_total: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let registry = Registry::new();
+
+        let connections_total = IntCounter::new(
+            "ufo_connections_total",
+            "Total frontend client connections accepted",
+        )
+        .expect("metric");
+        let disconnections_total = IntCounter::new(
+            "ufo_disconnections_total",
+            "Total frontend client disconnections",
+        )
+        .expect("metric");
+        let clients_connected = IntGauge::new(
+            "ufo_clients_connected",
+            "Currently connected frontend clients",
+        )
+        .expect("metric");
+        let subscriptions = IntGauge::new("ufo_subscriptions", "Active subscriptions, across all channels")
+            .expect("metric");
+        let broadcasts_total = IntCounter::new("ufo_broadcasts_total", "Broadcasts published, across all channels")
+            .expect("metric");
+        let bytes_sent_total = IntCounter::new(
+            "ufo_bytes_sent_total",
+            "Total bytes sent to frontend clients",
+        )
+        .expect("metric");
+        let backend_frames_total = IntCounterVec::new(
+            Opts::new(
+                "ufo_backend_frames_total",
+                "Backend command frames processed, by outcome",
+            ),
+            &["result"],
+        )
+        .expect("metric");
+
+        registry
+            .register(Box::new(connections_total.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(disconnections_total.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(clients_connected.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(subscriptions.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(broadcasts_total.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(bytes_sent_total.clone()))
+            .expect("register metric");
+        registry
+            .register(Box::new(backend_frames_total.clone()))
+            .expect("register metric");
+
+        Self {
+            registry,
+            connections_total,
+            disconnections_total,
+            clients_connected,
+            subscriptions,
+            broadcasts_total,
+            bytes_sent_total,
+            backend_frames_total,
+        }
+    }
+
+    /// Renders all registered metrics in Prometheus text exposition format.
+    pub(crate) fn encode(&self) -> String {
+        let metric_families = self.registry.gather();
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&metric_families, &mut buffer) {
+            error!("Encoding metrics: {:?}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -0,0 +1,117 @@
+//! NATS-style subject matching for channel names.
+//!
+//! Channel names are dot-separated tokens. A pattern token of `*` matches
+//! exactly one token, and a trailing `>` matches one or more trailing
+//! tokens. `matches` resolves which subscribers a broadcast on a concrete
+//! channel reaches; `authorizes` checks whether a (possibly wildcarded)
+//! permission grant is broad enough to cover a (possibly wildcarded)
+//! subscription pattern.
+
+fn tokenize(subject: &str) -> Vec<&str> {
+    subject.split('.').collect()
+}
+
+/// Returns true if the channel `subject` is matched by `pattern`.
+/// `subject` is expected to be a concrete, published channel;
+/// for checking whether one wildcarded pattern is authorized by another,
+/// use `authorizes` instead.
+pub(crate) fn matches(pattern: &str, subject: &str) -> bool {
+    matches_tokens(&tokenize(pattern), &tokenize(subject))
+}
+
+fn matches_tokens(pattern: &[&str], subject: &[&str]) -> bool {
+    match pattern.split_first() {
+        Some((&">", rest)) => rest.is_empty() && !subject.is_empty(),
+        Some((&token, prest)) => match subject.split_first() {
+            Some((&subj_token, srest)) => {
+                (token == "*" || token == subj_token) && matches_tokens(prest, srest)
+            }
+            None => false,
+        },
+        None => subject.is_empty(),
+    }
+}
+
+/// Returns true if every concrete channel `pattern` can match is also
+/// matched by `permission`, i.e. granting `permission` authorizes
+/// subscribing to `pattern` even when `pattern` itself carries wildcards.
+/// This is stricter than `matches`: a wildcard on the `pattern` side is
+/// only authorized if it cannot reach outside what `permission` grants
+/// (e.g. `orders.*` does not authorize `orders.>`, since the latter can
+/// match channels the former cannot).
+pub(crate) fn authorizes(permission: &str, pattern: &str) -> bool {
+    authorizes_tokens(&tokenize(permission), &tokenize(pattern))
+}
+
+fn authorizes_tokens(permission: &[&str], pattern: &[&str]) -> bool {
+    match permission.split_first() {
+        Some((&">", rest)) => rest.is_empty() && !pattern.is_empty(),
+        Some((&perm_token, prest)) => match pattern.split_first() {
+            Some((&pat_token, srest)) => {
+                if pat_token == ">" {
+                    false
+                } else {
+                    (perm_token == "*" || perm_token == pat_token)
+                        && authorizes_tokens(prest, srest)
+                }
+            }
+            None => false,
+        },
+        None => pattern.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_exact_subject() {
+        assert!(matches("orders.created", "orders.created"));
+        assert!(!matches("orders.created", "orders.shipped"));
+    }
+
+    #[test]
+    fn matches_single_token_wildcard() {
+        assert!(matches("orders.*", "orders.created"));
+        assert!(matches("orders.*.created", "orders.us.created"));
+        assert!(!matches("orders.*", "orders.us.created"));
+        assert!(!matches("orders.*", "orders"));
+    }
+
+    #[test]
+    fn matches_trailing_wildcard() {
+        assert!(matches("orders.>", "orders.created"));
+        assert!(matches("orders.>", "orders.us.created"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(!matches("orders.created.>", "orders.created"));
+    }
+
+    #[test]
+    fn authorizes_exact_and_narrower_patterns() {
+        assert!(authorizes("orders.created", "orders.created"));
+        assert!(authorizes("orders.>", "orders.created"));
+        assert!(authorizes("orders.>", "orders.us.created"));
+        assert!(authorizes("orders.*", "orders.created"));
+    }
+
+    #[test]
+    fn authorizes_rejects_patterns_broader_than_the_permission() {
+        // A permission scoped to exactly one token must not authorize a
+        // subscription that can reach an entire subtree.
+        assert!(!authorizes("orders.*", "orders.>"));
+        assert!(!authorizes("orders.created", "orders.>"));
+        assert!(!authorizes("orders.created", "orders.*"));
+        assert!(!authorizes("orders.*", "orders.*.created"));
+    }
+
+    #[test]
+    fn matches_and_authorizes_disagree_on_wildcard_subjects() {
+        // matches() treats its second argument as a concrete subject, so it
+        // wrongly allows a wildcard there to look "more specific" than it
+        // is; authorizes() is the one that must be used for permission
+        // checks against a possibly-wildcarded subscription pattern.
+        assert!(matches("orders.*", "orders.>"));
+        assert!(!authorizes("orders.*", "orders.>"));
+    }
+}